@@ -9,30 +9,120 @@ mod token;
 
 use parse::Word;
 
-fn extract_command_names(s: &str) -> Option<Vec<String>> {
+fn extract_command_names(s: &str, raw: bool) -> Result<Vec<String>, parse::Error> {
+    let s = if raw { s } else { strip_history_prefix(s) };
     let mut parser = parse::Parser::new(s);
 
+    Ok(command_names(&parser.parse()?))
+}
+
+/// Strips metadata that `history` prepends to a line before it reaches the
+/// parser: a leading history index (`  142  ls -la`) or the zsh
+/// extended-history `: <epoch>:<duration>;` header.
+fn strip_history_prefix(s: &str) -> &str {
+    let s = s.trim_start();
+
+    strip_zsh_extended_history(s).unwrap_or_else(|| strip_history_index(s))
+}
+
+/// Strips a zsh extended-history `: <epoch>:<duration>;` header, if present.
+fn strip_zsh_extended_history(s: &str) -> Option<&str> {
+    let rest = s.strip_prefix(':')?.trim_start();
+    let (epoch, rest) = rest.split_once(':')?;
+    let (duration, rest) = rest.split_once(';')?;
+
+    if is_digits(epoch) && is_digits(duration) {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Strips a leading history index, i.e. a run of digits followed by
+/// whitespace. A run of digits with nothing after it is left alone, since
+/// it's the whole line rather than an index prefix.
+fn strip_history_index(s: &str) -> &str {
+    let digits_end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let rest = &s[digits_end..];
+    let command = rest.trim_start();
+
+    if digits_end > 0 && rest.starts_with(|c: char| c.is_whitespace()) && !command.is_empty() {
+        command
+    } else {
+        s
+    }
+}
+
+fn is_digits(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Collects command names from a word list: the first word of the list and
+/// the first word following each `|`/`&&`/`||`/`;`, descending into any
+/// `$(...)`/`` `...` ``/`(...)` substitution to collect its own commands too.
+fn command_names(words: &[Word]) -> Vec<String> {
     let mut commands = Vec::new();
     let mut is_first_word = true;
 
-    if let Ok(words) = parser.parse() {
-        for word in words {
-            match word {
-                Word::String(command) if is_first_word => {
-                    commands.push(command);
-                    is_first_word = false;
-                }
-                Word::And | Word::Or | Word::Pipe | Word::Terminator => {
-                    is_first_word = true;
+    for word in words {
+        match word {
+            Word::String(command) if is_first_word => {
+                commands.push(command.clone());
+                is_first_word = false;
+            }
+            Word::And | Word::Or | Word::Pipe | Word::Terminator => {
+                is_first_word = true;
+            }
+            Word::Substitution(inner) => {
+                commands.extend(command_names(inner));
+                is_first_word = false;
+            }
+            Word::Quoted { substitutions, .. } => {
+                for substitution in substitutions {
+                    if let Word::Substitution(inner) = substitution {
+                        commands.extend(command_names(inner));
+                    }
                 }
-                _ => continue,
+                is_first_word = false;
+            }
+            Word::Fd(_) | Word::RedirectOut | Word::RedirectIn | Word::RedirectAppend => {
+                // Neither the redirection itself nor the filename word that
+                // follows it is a command name.
+                is_first_word = false;
             }
+            _ => continue,
         }
-    } else {
-        return None;
     }
 
-    Some(commands)
+    commands
+}
+
+/// Render a parse error as a human-readable message, including "line N,
+/// column M" for errors that carry a position (end-of-input errors don't).
+fn describe_parse_error(e: &parse::Error) -> String {
+    match e {
+        parse::Error::NoCloseDoubleQuote { at } => {
+            format!(
+                "unterminated quote at line {}, column {}",
+                at.line, at.column
+            )
+        }
+        parse::Error::UnclosedSubstitution { at } => {
+            format!(
+                "unclosed substitution at line {}, column {}",
+                at.line, at.column
+            )
+        }
+        parse::Error::TokenErr(token::Error::InvalidEscape { at }) => {
+            format!(
+                "invalid escape sequence at line {}, column {}",
+                at.line, at.column
+            )
+        }
+        parse::Error::Eos | parse::Error::TokenErr(token::Error::Eos) => {
+            "unexpected end of input".to_string()
+        }
+    }
 }
 
 fn help() {
@@ -51,8 +141,12 @@ Usages:
   history -n 1 | tk
 
 Options:
-  -h, --help    Show this help message and exit.
-  --version     Show version info and exit.
+  -h, --help        Show this help message and exit.
+  --version         Show version info and exit.
+  --json            Print the report as a JSON array instead of a text table.
+  --top N           Print only the N most frequent commands.
+  --min-count K     Omit commands counted fewer than K times.
+  --raw             Don't strip history indices or zsh timestamp metadata.
 "
     )
 }
@@ -61,8 +155,102 @@ fn version() {
     println!("{}", env!("CARGO_PKG_VERSION"));
 }
 
+/// How `report` should filter and render the frequency table, and (`raw`)
+/// whether input lines are parsed without stripping history metadata.
+#[derive(Debug, Default)]
+struct Options {
+    json: bool,
+    top: Option<usize>,
+    min_count: usize,
+    raw: bool,
+}
+
+/// Parses the value following a flag like `--top`, returning a diagnostic
+/// message if it's missing or isn't a number.
+fn parse_flag_value(value: Option<String>, flag: &str) -> Result<usize, String> {
+    let value = value.ok_or_else(|| format!("{} requires a value", flag))?;
+
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a number, got '{}'", flag, value))
+}
+
+/// Reads the value following a flag like `--top` and parses it, exiting with
+/// a diagnostic if the flag is missing its value or the value isn't a number.
+fn next_flag_value(args: &mut impl Iterator<Item = String>, flag: &str) -> usize {
+    parse_flag_value(args.next(), flag).unwrap_or_else(|message| {
+        eprintln!("{}: {}", env!("CARGO_PKG_NAME"), message);
+        process::exit(1);
+    })
+}
+
+/// Escapes `s` for use inside a JSON string literal.
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Sorts `map` by descending count and applies `opts.min_count`/`opts.top`.
+fn sorted_rows(map: HashMap<String, usize>, opts: &Options) -> Vec<(usize, String)> {
+    let mut vec: Vec<(usize, String)> = map
+        .into_iter()
+        .filter(|(_, v)| *v >= opts.min_count)
+        .map(|(k, v)| (v, k))
+        .collect();
+    vec.sort();
+    vec.reverse();
+
+    if let Some(top) = opts.top {
+        vec.truncate(top);
+    }
+
+    vec
+}
+
+/// Prints `sorted_rows(map, opts)` either as the default right-aligned text
+/// table or, if `opts.json` is set, as a JSON array of
+/// `{command, count, fraction}`.
+fn report(map: HashMap<String, usize>, total: usize, opts: &Options) {
+    let vec = sorted_rows(map, opts);
+
+    if opts.json {
+        let rows: Vec<String> = vec
+            .into_iter()
+            .map(|(v, k)| {
+                format!(
+                    "{{\"command\":\"{}\",\"count\":{},\"fraction\":{:.6}}}",
+                    escape_json(&k),
+                    v,
+                    (v as f64) / (total as f64)
+                )
+            })
+            .collect();
+
+        println!("[{}]", rows.join(","));
+    } else {
+        for (v, k) in vec {
+            println!("{:>10}({:.6}) {}", v, (v as f64) / (total as f64), k);
+        }
+    }
+}
+
 fn main() {
-    for arg in env::args().skip(1) {
+    let mut opts = Options::default();
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
         match arg.as_str() {
             "-h" | "--help" => {
                 help();
@@ -72,6 +260,10 @@ fn main() {
                 version();
                 process::exit(0);
             }
+            "--json" => opts.json = true,
+            "--top" => opts.top = Some(next_flag_value(&mut args, "--top")),
+            "--min-count" => opts.min_count = next_flag_value(&mut args, "--min-count"),
+            "--raw" => opts.raw = true,
             _ => {
                 eprintln!("{}: Invalid option - {}", env!("CARGO_PKG_NAME"), arg);
                 process::exit(0);
@@ -91,10 +283,20 @@ fn main() {
             break;
         }
 
-        if let Some(cmds) = extract_command_names(&s) {
-            for cmd in cmds {
-                let counter = map.entry(cmd).or_insert(0usize);
-                *counter += 1;
+        match extract_command_names(&s, opts.raw) {
+            Ok(cmds) => {
+                for cmd in cmds {
+                    let counter = map.entry(cmd).or_insert(0usize);
+                    *counter += 1;
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "{}: history entry {}: {}",
+                    env!("CARGO_PKG_NAME"),
+                    count + 1,
+                    describe_parse_error(&e)
+                );
             }
         }
 
@@ -102,11 +304,96 @@ fn main() {
         s.clear();
     }
 
-    let mut vec: Vec<(usize, String)> = map.into_iter().map(|(k, v)| (v, k)).collect();
-    vec.sort();
-    vec.reverse();
+    report(map, count, &opts);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_history_prefix() {
+        assert_eq!(strip_history_prefix("  142  ls -la"), "ls -la");
+        assert_eq!(strip_history_prefix(": 1700000000:0;echo hi"), "echo hi");
+        assert_eq!(strip_history_prefix("ls -la"), "ls -la");
+        // A digit-glued word isn't an index: no whitespace follows it.
+        assert_eq!(strip_history_prefix("123abc"), "123abc");
+        // A line that's only digits is left alone; there's no command left
+        // to strip it down to.
+        assert_eq!(strip_history_prefix("142"), "142");
+    }
+
+    #[test]
+    fn test_strip_zsh_extended_history() {
+        assert_eq!(
+            strip_zsh_extended_history(": 1700000000:0;echo hi"),
+            Some("echo hi")
+        );
+        assert_eq!(strip_zsh_extended_history("ls -la"), None);
+        // Malformed headers (non-numeric epoch/duration) are left alone.
+        assert_eq!(strip_zsh_extended_history(": foo:0;echo hi"), None);
+        assert_eq!(strip_zsh_extended_history(": 1700000000:bar;echo hi"), None);
+    }
+
+    #[test]
+    fn test_strip_history_index() {
+        assert_eq!(strip_history_index("  142  ls -la"), "  142  ls -la");
+        assert_eq!(strip_history_index("142  ls -la"), "ls -la");
+        assert_eq!(strip_history_index("123abc"), "123abc");
+        assert_eq!(strip_history_index("142"), "142");
+    }
+
+    #[test]
+    fn test_escape_json() {
+        assert_eq!(escape_json("plain"), "plain");
+        assert_eq!(escape_json(r#"say "hi""#), r#"say \"hi\""#);
+        assert_eq!(escape_json("line1\nline2"), r"line1\nline2");
+        assert_eq!(escape_json("a\\b"), r"a\\b");
+    }
+
+    #[test]
+    fn test_parse_flag_value() {
+        assert_eq!(parse_flag_value(Some(String::from("3")), "--top"), Ok(3));
+        assert_eq!(
+            parse_flag_value(None, "--top"),
+            Err(String::from("--top requires a value"))
+        );
+        assert_eq!(
+            parse_flag_value(Some(String::from("nope")), "--top"),
+            Err(String::from("--top expects a number, got 'nope'"))
+        );
+    }
+
+    #[test]
+    fn test_sorted_rows() {
+        let mut map = HashMap::new();
+        map.insert(String::from("ls"), 2);
+        map.insert(String::from("grep"), 1);
+        map.insert(String::from("echo"), 1);
+
+        let opts = Options::default();
+        assert_eq!(
+            sorted_rows(map.clone(), &opts),
+            vec![
+                (2, String::from("ls")),
+                (1, String::from("grep")),
+                (1, String::from("echo")),
+            ]
+        );
+
+        let opts = Options {
+            top: Some(1),
+            ..Options::default()
+        };
+        assert_eq!(
+            sorted_rows(map.clone(), &opts),
+            vec![(2, String::from("ls"))]
+        );
 
-    for (v, k) in vec {
-        println!("{:>10}({:.6}) {}", v, (v as f64) / (count as f64), k);
+        let opts = Options {
+            min_count: 2,
+            ..Options::default()
+        };
+        assert_eq!(sorted_rows(map, &opts), vec![(2, String::from("ls"))]);
     }
 }
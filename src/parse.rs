@@ -1,9 +1,11 @@
 use crate::token;
+use crate::token::Position;
 use crate::token::Token;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
-    NoCloseDoubleQuote,
+    NoCloseDoubleQuote { at: Position },
+    UnclosedSubstitution { at: Position },
     Eos,
     TokenErr(token::Error),
 }
@@ -23,17 +25,42 @@ pub enum Word {
     Terminator,
     String(String),
     Variable(Variable),
+    /// The words inside a `$(...)`, `` `...` ``, or `(...)`.
+    Substitution(Vec<Word>),
+    /// A double-quoted word containing one or more embedded `$(...)`,
+    /// `` `...` ``, or `(...)` substitutions. `text` is the decoded literal
+    /// content with each substitution elided, since we don't execute them;
+    /// `substitutions` holds each one as a `Word::Substitution`, so their
+    /// commands are still collected.
+    Quoted {
+        text: String,
+        substitutions: Vec<Word>,
+    },
+    /// A file descriptor number prefixing a redirection, e.g. the `2` in
+    /// `2>err.log`.
+    Fd(u32),
+    /// `>`
+    RedirectOut,
+    /// `<`
+    RedirectIn,
+    /// `>>`
+    RedirectAppend,
 }
 
 #[derive(Debug, Clone)]
 pub struct Parser<'a> {
     tokenizer: token::Tokenizer<'a>,
+    // The closer (`)` or `` ` ``) of each substitution we're currently
+    // nested inside, innermost last. `next_word` stops, without consuming,
+    // as soon as it sees the token on top of this stack.
+    closer_stack: Vec<Token<'a>>,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(s: &'a str) -> Parser<'a> {
         Parser {
             tokenizer: token::Tokenizer::new(s),
+            closer_stack: Vec::new(),
         }
     }
 
@@ -56,6 +83,12 @@ impl<'a> Parser<'a> {
         let mut is_somethihg_found = false;
 
         loop {
+            if let Ok(ref t) = self.peek_token() {
+                if Some(t) == self.closer_stack.last() {
+                    break;
+                }
+            }
+
             match self.peek_token() {
                 Ok(Token::Ampersand) => {
                     let _ = self.next();
@@ -85,7 +118,7 @@ impl<'a> Parser<'a> {
                         return Ok(Word::Terminator);
                     }
                 }
-                Ok(Token::Spaces { .. }) => {
+                Ok(Token::Spaces { .. } | Token::Comment { .. }) => {
                     let _ = self.next();
 
                     if is_somethihg_found {
@@ -94,13 +127,73 @@ impl<'a> Parser<'a> {
                         continue;
                     }
                 }
-                Ok(Token::DoubleQuote) => {
+                Ok(Token::DoubleQuote) => match self.next() {
+                    Ok(spanned) => match self.double_quoted_string(spanned.start) {
+                        Ok((text, substitutions)) if substitutions.is_empty() => {
+                            return Ok(Word::String(text))
+                        }
+                        Ok((text, substitutions)) => {
+                            return Ok(Word::Quoted {
+                                text,
+                                substitutions,
+                            })
+                        }
+                        Err(e) => return Err(e),
+                    },
+                    Err(e) => return Err(Error::TokenErr(e)),
+                },
+                Ok(Token::Dollar)
+                    if is_somethihg_found
+                        && matches!(self.peek_second_token(), Ok(Token::OpenParenthesis)) =>
+                {
+                    break;
+                }
+                Ok(Token::Dollar) => match self.next() {
+                    Ok(spanned) if self.eat_token(Token::OpenParenthesis) => {
+                        let words = self.substitution(Token::CloseParenthesis, spanned.start)?;
+                        return Ok(Word::Substitution(words));
+                    }
+                    Ok(_) => value.push('$'),
+                    Err(e) => return Err(Error::TokenErr(e)),
+                },
+                Ok(Token::OpenParenthesis | Token::Backtick) if is_somethihg_found => {
+                    break;
+                }
+                Ok(Token::OpenParenthesis) => match self.next() {
+                    Ok(spanned) => {
+                        let words = self.substitution(Token::CloseParenthesis, spanned.start)?;
+                        return Ok(Word::Substitution(words));
+                    }
+                    Err(e) => return Err(Error::TokenErr(e)),
+                },
+                Ok(Token::Backtick) => match self.next() {
+                    Ok(spanned) => {
+                        let words = self.substitution(Token::Backtick, spanned.start)?;
+                        return Ok(Word::Substitution(words));
+                    }
+                    Err(e) => return Err(Error::TokenErr(e)),
+                },
+                Ok(Token::GreaterThan) => {
+                    if is_somethihg_found {
+                        break;
+                    }
+
                     let _ = self.next();
 
-                    match self.double_quoted_string() {
-                        Ok(s) => return Ok(Word::String(s)),
-                        Err(e) => return Err(e),
+                    if self.eat_token(Token::GreaterThan) {
+                        return Ok(Word::RedirectAppend);
+                    } else {
+                        return Ok(Word::RedirectOut);
+                    }
+                }
+                Ok(Token::LesserThan) => {
+                    if is_somethihg_found {
+                        break;
                     }
+
+                    let _ = self.next();
+
+                    return Ok(Word::RedirectIn);
                 }
                 Ok(token @ Token::String { .. }) => {
                     let _ = self.next();
@@ -111,8 +204,17 @@ impl<'a> Parser<'a> {
                         let value = self.value()?;
 
                         return Ok(Word::Variable(Variable { name, value }));
+                    } else if let Ok(fd) = name.parse::<u32>() {
+                        if matches!(
+                            self.peek_token(),
+                            Ok(Token::GreaterThan | Token::LesserThan)
+                        ) {
+                            return Ok(Word::Fd(fd));
+                        }
+
+                        value.push_str(token::decode_unquoted(&name).as_str());
                     } else {
-                        value.push_str(name.as_str());
+                        value.push_str(token::decode_unquoted(&name).as_str());
                     }
                 }
                 Ok(token) => {
@@ -144,20 +246,24 @@ impl<'a> Parser<'a> {
         loop {
             match self.peek_token() {
                 Ok(Token::DoubleQuote) => {
-                    let _ = self.next();
-                    let s = self.double_quoted_string()?;
+                    let at = match self.next() {
+                        Ok(spanned) => spanned.start,
+                        Err(e) => return Err(Error::TokenErr(e)),
+                    };
+                    let (s, _) = self.double_quoted_string(at)?;
                     return Ok(s);
                 }
                 Ok(
                     Token::Newline
                     | Token::Semicolon
                     | Token::Spaces { .. }
+                    | Token::Comment { .. }
                     | Token::Ampersand
                     | Token::VerticalBar,
                 ) => break,
                 Ok(token) => {
                     let _ = self.next();
-                    s.push_str(String::from(token).as_str());
+                    s.push_str(decode_if_string(token).as_str());
                 }
                 Err(e) => return Err(Error::TokenErr(e)),
             }
@@ -166,22 +272,90 @@ impl<'a> Parser<'a> {
         Ok(s)
     }
 
-    fn double_quoted_string(&mut self) -> Result<String, Error> {
+    /// Parses the body of a double-quoted string, decoding escape sequences
+    /// and recursing into any embedded `$(...)`, `` `...` ``, or `(...)`
+    /// substitution exactly like the unquoted path does. Returns the literal
+    /// text, with each substitution elided, alongside each substitution's
+    /// own parsed words, in the order they appeared.
+    fn double_quoted_string(&mut self, at: Position) -> Result<(String, Vec<Word>), Error> {
         let mut s = String::new();
+        let mut substitutions = Vec::new();
 
         loop {
-            match self.next() {
-                Ok(Token::DoubleQuote) => return Ok(s),
-                Ok(token) => {
-                    s.push_str(String::from(token).as_str());
+            match self.peek_token() {
+                Ok(Token::DoubleQuote) => {
+                    let _ = self.next();
+                    return Ok((s, substitutions));
+                }
+                Ok(Token::Dollar) => {
+                    let start = match self.next() {
+                        Ok(spanned) => spanned.start,
+                        Err(e) => return Err(Error::TokenErr(e)),
+                    };
+
+                    if self.eat_token(Token::OpenParenthesis) {
+                        let words = self.substitution(Token::CloseParenthesis, start)?;
+                        substitutions.push(Word::Substitution(words));
+                    } else {
+                        s.push('$');
+                    }
+                }
+                Ok(Token::OpenParenthesis) => {
+                    let start = match self.next() {
+                        Ok(spanned) => spanned.start,
+                        Err(e) => return Err(Error::TokenErr(e)),
+                    };
+                    let words = self.substitution(Token::CloseParenthesis, start)?;
+                    substitutions.push(Word::Substitution(words));
+                }
+                Ok(Token::Backtick) => {
+                    let start = match self.next() {
+                        Ok(spanned) => spanned.start,
+                        Err(e) => return Err(Error::TokenErr(e)),
+                    };
+                    let words = self.substitution(Token::Backtick, start)?;
+                    substitutions.push(Word::Substitution(words));
                 }
-                Err(token::Error::Eos) => return Err(Error::NoCloseDoubleQuote),
+                Ok(_) => match self.next() {
+                    Ok(spanned) => {
+                        s.push_str(decode_double_quoted_if_string(spanned.value).as_str());
+                    }
+                    Err(e) => return Err(Error::TokenErr(e)),
+                },
+                Err(token::Error::Eos) => return Err(Error::NoCloseDoubleQuote { at }),
                 Err(e) => return Err(Error::TokenErr(e)),
             }
         }
     }
 
-    fn next(&mut self) -> Result<token::Token<'a>, token::Error> {
+    /// Parses words until `closer` is seen, consuming it. `next_word` is
+    /// made to stop at `closer` via `closer_stack`, so nested substitutions
+    /// (pushed by `next_word` recursing back into this method) don't cause
+    /// it to be mistaken for an inner one's closer.
+    fn substitution(&mut self, closer: Token<'a>, at: Position) -> Result<Vec<Word>, Error> {
+        self.closer_stack.push(closer);
+        let mut words = Vec::new();
+
+        let result = loop {
+            match self.peek_token() {
+                Ok(ref t) if Some(t) == self.closer_stack.last() => {
+                    let _ = self.next();
+                    break Ok(words);
+                }
+                Ok(_) => match self.next_word() {
+                    Ok(word) => words.push(word),
+                    Err(e) => break Err(e),
+                },
+                Err(token::Error::Eos) => break Err(Error::UnclosedSubstitution { at }),
+                Err(e) => break Err(Error::TokenErr(e)),
+            }
+        };
+
+        self.closer_stack.pop();
+        result
+    }
+
+    fn next(&mut self) -> Result<token::Spanned<token::Token<'a>>, token::Error> {
         self.tokenizer.next()
     }
 
@@ -189,11 +363,40 @@ impl<'a> Parser<'a> {
         self.tokenizer.peek_token()
     }
 
+    /// Like `peek_token`, but looks one token further ahead, without
+    /// consuming either.
+    fn peek_second_token(&self) -> Result<token::Token<'a>, token::Error> {
+        let mut tokenizer = self.tokenizer.clone();
+        tokenizer.next()?;
+        tokenizer.next().map(|spanned| spanned.value)
+    }
+
     fn eat_token(&mut self, token: token::Token) -> bool {
         self.tokenizer.eat_token(token)
     }
 }
 
+/// Decodes `token` with unquoted-word escape rules if it's a `Token::String`,
+/// otherwise stringifies it unchanged (e.g. operator chars glued onto a word).
+fn decode_if_string(token: Token<'_>) -> String {
+    match token {
+        Token::String { .. } => token::decode_unquoted(&String::from(token))
+            .as_str()
+            .to_string(),
+        token => String::from(token),
+    }
+}
+
+/// Like `decode_if_string`, but using double-quote escape rules.
+fn decode_double_quoted_if_string(token: Token<'_>) -> String {
+    match token {
+        Token::String { .. } => token::decode_double_quoted(&String::from(token))
+            .as_str()
+            .to_string(),
+        token => String::from(token),
+    }
+}
+
 fn is_variable_name(s: &str) -> bool {
     for (i, c) in s.char_indices() {
         if i == 0 && c.is_ascii_digit() {
@@ -267,4 +470,222 @@ mod tests {
             ])
         );
     }
+
+    #[test]
+    fn test_parser_discards_comments() {
+        let s = "ls -la # list everything";
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("ls")),
+                Word::String(String::from("-la")),
+            ])
+        );
+
+        let s = "foo#bar";
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Word::String(String::from("foo#bar"))])
+        );
+    }
+
+    #[test]
+    fn test_parser_parses_substitutions() {
+        let s = "echo $(date) `whoami` (cd /tmp && ls)";
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("echo")),
+                Word::Substitution(vec![Word::String(String::from("date"))]),
+                Word::Substitution(vec![Word::String(String::from("whoami"))]),
+                Word::Substitution(vec![
+                    Word::String(String::from("cd")),
+                    Word::String(String::from("/tmp")),
+                    Word::And,
+                    Word::String(String::from("ls")),
+                ]),
+            ])
+        );
+
+        let s = "echo $(echo $(date))";
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("echo")),
+                Word::Substitution(vec![
+                    Word::String(String::from("echo")),
+                    Word::Substitution(vec![Word::String(String::from("date"))]),
+                ]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parser_substitution_does_not_swallow_adjacent_text() {
+        let s = "rm file(1).txt";
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("rm")),
+                Word::String(String::from("file")),
+                Word::Substitution(vec![Word::String(String::from("1"))]),
+                Word::String(String::from(".txt")),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parser_parses_substitutions_in_double_quoted_strings() {
+        let s = r#"echo "$(date)""#;
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("echo")),
+                Word::Quoted {
+                    text: String::new(),
+                    substitutions: vec![Word::Substitution(vec![Word::String(String::from(
+                        "date"
+                    ))])],
+                },
+            ])
+        );
+
+        let s = r#"echo "pre $(date) post""#;
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("echo")),
+                Word::Quoted {
+                    text: String::from("pre  post"),
+                    substitutions: vec![Word::Substitution(vec![Word::String(String::from(
+                        "date"
+                    ))])],
+                },
+            ])
+        );
+
+        let s = r#"echo "`whoami`""#;
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("echo")),
+                Word::Quoted {
+                    text: String::new(),
+                    substitutions: vec![Word::Substitution(vec![Word::String(String::from(
+                        "whoami"
+                    ))])],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parser_unclosed_substitution() {
+        let s = "echo $(date";
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Err(Error::UnclosedSubstitution {
+                at: Position { line: 1, column: 6 }
+            })
+        );
+    }
+
+    #[test]
+    fn test_parser_parses_redirections() {
+        let s = "ls > out.txt";
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("ls")),
+                Word::RedirectOut,
+                Word::String(String::from("out.txt")),
+            ])
+        );
+
+        let s = "sort < in.txt >> out.txt";
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("sort")),
+                Word::RedirectIn,
+                Word::String(String::from("in.txt")),
+                Word::RedirectAppend,
+                Word::String(String::from("out.txt")),
+            ])
+        );
+
+        let s = "cmd 2> err.log";
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("cmd")),
+                Word::Fd(2),
+                Word::RedirectOut,
+                Word::String(String::from("err.log")),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parser_decodes_escapes() {
+        let s = r"echo foo\ bar";
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("echo")),
+                Word::String(String::from("foo bar")),
+            ])
+        );
+
+        let s = r#"echo "say \"hi\"""#;
+
+        let mut parser = Parser::new(s);
+
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Word::String(String::from("echo")),
+                Word::String(String::from(r#"say "hi""#)),
+            ])
+        );
+    }
 }
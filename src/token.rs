@@ -3,16 +3,112 @@ use std::convert::TryFrom;
 use std::ops::Range;
 use std::str;
 
+/// A 1-based line/column location within the tokenized input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    fn start() -> Position {
+        Position { line: 1, column: 1 }
+    }
+}
+
+/// A value paired with the `Position` at which it starts in the input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub start: Position,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Error {
-    MissingEscapedChar,
+    InvalidEscape { at: Position },
     Eos,
 }
 
+/// A string that borrows from the source unless decoding an escape forced
+/// an owned copy.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MaybeString<'a> {
+    Borrowed(&'a str),
+    Owned(String),
+}
+
+impl<'a> MaybeString<'a> {
+    pub fn as_str(&self) -> &str {
+        match self {
+            MaybeString::Borrowed(s) => s,
+            MaybeString::Owned(s) => s.as_str(),
+        }
+    }
+}
+
+/// Shell-style quote removal for an unquoted word: `\<char>` decodes to the
+/// literal `<char>`.
+pub fn decode_unquoted(raw: &str) -> MaybeString<'_> {
+    if !raw.contains('\\') {
+        return MaybeString::Borrowed(raw);
+    }
+
+    let mut s = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+
+        if let Some(escaped) = chars.next() {
+            s.push(escaped);
+        }
+    }
+
+    MaybeString::Owned(s)
+}
+
+/// Decodes the double-quote escape sequences `\"`, `\\`, `\$`, `` \` ``, and
+/// `\n`/`\t`. Any other `\<char>` is left as-is, backslash included.
+pub fn decode_double_quoted(raw: &str) -> MaybeString<'_> {
+    if !raw.contains('\\') {
+        return MaybeString::Borrowed(raw);
+    }
+
+    let mut s = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('"') => s.push('"'),
+            Some('\\') => s.push('\\'),
+            Some('$') => s.push('$'),
+            Some('`') => s.push('`'),
+            Some('n') => s.push('\n'),
+            Some('t') => s.push('\t'),
+            Some(other) => {
+                s.push('\\');
+                s.push(other);
+            }
+            None => {}
+        }
+    }
+
+    MaybeString::Owned(s)
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token<'a> {
     Ampersand,
     Asterisk,
+    Backtick,
     CloseBrace,
     CloseBracket,
     CloseParenthesis,
@@ -29,6 +125,7 @@ pub enum Token<'a> {
     Tilda,
     VerticalBar,
 
+    Comment { s: &'a str, range: Range<usize> },
     Spaces { s: &'a str, range: Range<usize> },
     String { s: &'a str, range: Range<usize> },
     QuotedString { s: &'a str, range: Range<usize> },
@@ -41,6 +138,7 @@ impl TryFrom<Token<'_>> for char {
         match token {
             Token::Ampersand => Ok('&'),
             Token::Asterisk => Ok('*'),
+            Token::Backtick => Ok('`'),
             Token::CloseBrace => Ok('}'),
             Token::CloseBracket => Ok(']'),
             Token::CloseParenthesis => Ok(')'),
@@ -68,6 +166,7 @@ impl TryFrom<char> for Token<'_> {
         match c {
             '&' => Ok(Token::Ampersand),
             '*' => Ok(Token::Asterisk),
+            '`' => Ok(Token::Backtick),
             '}' => Ok(Token::CloseBrace),
             ']' => Ok(Token::CloseBracket),
             ')' => Ok(Token::CloseParenthesis),
@@ -91,7 +190,8 @@ impl TryFrom<char> for Token<'_> {
 impl From<Token<'_>> for String {
     fn from(token: Token) -> String {
         match token {
-            Token::Spaces { s, range }
+            Token::Comment { s, range }
+            | Token::Spaces { s, range }
             | Token::String { s, range }
             | Token::QuotedString { s, range } => s[range].to_string(),
             _ => String::from(char::try_from(token).unwrap()),
@@ -103,13 +203,23 @@ impl From<Token<'_>> for String {
 pub struct Tokenizer<'a> {
     input: &'a str,
     chars: str::CharIndices<'a>,
+    line: usize,
+    column: usize,
+    // Whether the next char starts at a word boundary, i.e. a `#` seen here
+    // opens a comment rather than being an ordinary string char.
+    at_boundary: bool,
 }
 
 impl<'a> Tokenizer<'a> {
     pub fn new(s: &'a str) -> Tokenizer<'a> {
+        let start = Position::start();
+
         Tokenizer {
             input: s,
             chars: s.char_indices(),
+            line: start.line,
+            column: start.column,
+            at_boundary: true,
         }
     }
 
@@ -124,24 +234,48 @@ impl<'a> Tokenizer<'a> {
     }
 
     pub fn peek_token(&mut self) -> Result<Token<'a>, Error> {
-        self.clone().next()
+        self.clone().next().map(|spanned| spanned.value)
     }
 
-    pub fn next(&mut self) -> Result<Token<'a>, Error> {
-        match self.one() {
+    pub fn next(&mut self) -> Result<Spanned<Token<'a>>, Error> {
+        let start = self.position();
+        let at_boundary = self.at_boundary;
+
+        let value = match self.one() {
             Some((start, '\'')) => {
                 let end = self.single_quoted_string()?;
-                Ok(Token::QuotedString {
+                Token::QuotedString {
                     s: self.input,
                     range: (start + 1)..end,
-                })
+                }
             }
             Some((start, ' ' | '\t')) => {
                 let end = self.spaces()?;
-                Ok(Token::Spaces {
+                Token::Spaces {
                     s: self.input,
                     range: start..end,
-                })
+                }
+            }
+            Some((start, '#')) if at_boundary => {
+                let end = self.comment();
+                Token::Comment {
+                    s: self.input,
+                    range: start..end,
+                }
+            }
+            Some((byte_start, '\\')) => {
+                // A leading backslash always escapes whatever follows, even
+                // if that char would otherwise start a new token, so it
+                // can't be split off the word it began.
+                if self.one().is_none() {
+                    return Err(Error::InvalidEscape { at: start });
+                }
+
+                let end = self.raw_string()?;
+                Token::String {
+                    s: self.input,
+                    range: byte_start..end,
+                }
             }
             Some((start, c)) => Token::try_from(c).or_else(|_| {
                 let end = self.raw_string()?;
@@ -149,8 +283,28 @@ impl<'a> Tokenizer<'a> {
                     s: self.input,
                     range: start..end,
                 })
-            }),
-            None => Err(Error::Eos),
+            })?,
+            None => return Err(Error::Eos),
+        };
+
+        self.at_boundary = matches!(
+            value,
+            Token::Spaces { .. }
+                | Token::Newline
+                | Token::Semicolon
+                | Token::Ampersand
+                | Token::VerticalBar
+        );
+
+        Ok(Spanned { value, start })
+    }
+
+    /// The `Position` of the next unconsumed char, i.e. where a token
+    /// produced by the following `next()` call would start.
+    fn position(&self) -> Position {
+        Position {
+            line: self.line,
+            column: self.column,
         }
     }
 
@@ -168,11 +322,13 @@ impl<'a> Tokenizer<'a> {
         loop {
             match self.peek_one() {
                 Some((_, '\\')) => {
+                    let at = self.position();
+
                     // Skip a \.
                     self.one();
                     // Skip an escaped char.
                     if self.one().is_none() {
-                        return Err(Error::MissingEscapedChar);
+                        return Err(Error::InvalidEscape { at });
                     }
                 }
                 Some((i, c)) => match Token::try_from(c) {
@@ -186,6 +342,18 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    /// Consumes up to, but not including, the next `'\n'` (or EOS).
+    fn comment(&mut self) -> usize {
+        loop {
+            match self.peek_one() {
+                Some((_, '\n')) | None => return self.current(),
+                Some(_) => {
+                    self.one();
+                }
+            }
+        }
+    }
+
     fn spaces(&mut self) -> Result<usize, Error> {
         while self.eatc(' ') || self.eatc('\t') {}
         Ok(self.current())
@@ -206,7 +374,18 @@ impl<'a> Tokenizer<'a> {
     }
 
     fn one(&mut self) -> Option<(usize, char)> {
-        self.chars.next()
+        let item = self.chars.next();
+
+        if let Some((_, c)) = item {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+
+        item
     }
 
     fn peek_one(&self) -> Option<(usize, char)> {
@@ -228,50 +407,431 @@ mod tests {
         let s = "echo   ";
         let mut tokenizer = Tokenizer::new(s);
 
-        assert_eq!(tokenizer.next(), Ok(Token::String { s, range: 0..4 }));
-        assert_eq!(tokenizer.next(), Ok(Token::Spaces { s, range: 4..7 }));
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 0..4 },
+                start: Position { line: 1, column: 1 },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Spaces { s, range: 4..7 },
+                start: Position { line: 1, column: 5 },
+            })
+        );
         assert_eq!(tokenizer.next(), Err(Error::Eos));
 
         let s = r#"  LS_COLORS='*.rs=38;5;81' var=hoge   haskellorls   '-ABFHhov'   "--color=auto"  --time-style=iso"#;
         let mut tokenizer = Tokenizer::new(s);
 
-        assert_eq!(tokenizer.next(), Ok(Token::Spaces { s, range: 0..2 }));
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Spaces { s, range: 0..2 },
+                start: Position { line: 1, column: 1 },
+            })
+        );
         // LS_COLORS
-        assert_eq!(tokenizer.next(), Ok(Token::String { s, range: 2..11 }));
-        assert_eq!(tokenizer.next(), Ok(Token::Equal));
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 2..11 },
+                start: Position { line: 1, column: 3 },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Equal,
+                start: Position {
+                    line: 1,
+                    column: 12
+                },
+            })
+        );
         // '*.rs=38;5;81'
         assert_eq!(
             tokenizer.next(),
-            Ok(Token::QuotedString { s, range: 13..25 })
+            Ok(Spanned {
+                value: Token::QuotedString { s, range: 13..25 },
+                start: Position {
+                    line: 1,
+                    column: 13
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Spaces { s, range: 26..27 },
+                start: Position {
+                    line: 1,
+                    column: 27
+                },
+            })
         );
-        assert_eq!(tokenizer.next(), Ok(Token::Spaces { s, range: 26..27 }));
         // var
-        assert_eq!(tokenizer.next(), Ok(Token::String { s, range: 27..30 }));
-        assert_eq!(tokenizer.next(), Ok(Token::Equal));
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 27..30 },
+                start: Position {
+                    line: 1,
+                    column: 28
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Equal,
+                start: Position {
+                    line: 1,
+                    column: 31
+                },
+            })
+        );
         // hoge
-        assert_eq!(tokenizer.next(), Ok(Token::String { s, range: 31..35 }));
-        assert_eq!(tokenizer.next(), Ok(Token::Spaces { s, range: 35..38 }));
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 31..35 },
+                start: Position {
+                    line: 1,
+                    column: 32
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Spaces { s, range: 35..38 },
+                start: Position {
+                    line: 1,
+                    column: 36
+                },
+            })
+        );
         // haskellorls
-        assert_eq!(tokenizer.next(), Ok(Token::String { s, range: 38..49 }));
-        assert_eq!(tokenizer.next(), Ok(Token::Spaces { s, range: 49..52 }));
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 38..49 },
+                start: Position {
+                    line: 1,
+                    column: 39
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Spaces { s, range: 49..52 },
+                start: Position {
+                    line: 1,
+                    column: 50
+                },
+            })
+        );
         // '-ABFHhov'
         assert_eq!(
             tokenizer.next(),
-            Ok(Token::QuotedString { s, range: 53..61 })
+            Ok(Spanned {
+                value: Token::QuotedString { s, range: 53..61 },
+                start: Position {
+                    line: 1,
+                    column: 53
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Spaces { s, range: 62..65 },
+                start: Position {
+                    line: 1,
+                    column: 63
+                },
+            })
         );
-        assert_eq!(tokenizer.next(), Ok(Token::Spaces { s, range: 62..65 }));
         // "--color=auto"
-        assert_eq!(tokenizer.next(), Ok(Token::DoubleQuote));
-        assert_eq!(tokenizer.next(), Ok(Token::String { s, range: 66..73 }));
-        assert_eq!(tokenizer.next(), Ok(Token::Equal));
-        assert_eq!(tokenizer.next(), Ok(Token::String { s, range: 74..78 }));
-        assert_eq!(tokenizer.next(), Ok(Token::DoubleQuote));
-        assert_eq!(tokenizer.next(), Ok(Token::Spaces { s, range: 79..81 }));
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::DoubleQuote,
+                start: Position {
+                    line: 1,
+                    column: 66
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 66..73 },
+                start: Position {
+                    line: 1,
+                    column: 67
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Equal,
+                start: Position {
+                    line: 1,
+                    column: 74
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 74..78 },
+                start: Position {
+                    line: 1,
+                    column: 75
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::DoubleQuote,
+                start: Position {
+                    line: 1,
+                    column: 79
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Spaces { s, range: 79..81 },
+                start: Position {
+                    line: 1,
+                    column: 80
+                },
+            })
+        );
         // --time-style
-        assert_eq!(tokenizer.next(), Ok(Token::String { s, range: 81..93 }));
-        assert_eq!(tokenizer.next(), Ok(Token::Equal));
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 81..93 },
+                start: Position {
+                    line: 1,
+                    column: 82
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Equal,
+                start: Position {
+                    line: 1,
+                    column: 94
+                },
+            })
+        );
         // iso
-        assert_eq!(tokenizer.next(), Ok(Token::String { s, range: 94..97 }));
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 94..97 },
+                start: Position {
+                    line: 1,
+                    column: 95
+                },
+            })
+        );
         assert_eq!(tokenizer.next(), Err(Error::Eos),);
     }
+
+    #[test]
+    fn test_position_tracks_newlines() {
+        let s = "echo\nworld";
+        let mut tokenizer = Tokenizer::new(s);
+
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 0..4 },
+                start: Position { line: 1, column: 1 },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Newline,
+                start: Position { line: 1, column: 5 },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 5..10 },
+                start: Position { line: 2, column: 1 },
+            })
+        );
+    }
+
+    #[test]
+    fn test_comment() {
+        let s = "echo hi # trailing note\nls";
+        let mut tokenizer = Tokenizer::new(s);
+
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 0..4 },
+                start: Position { line: 1, column: 1 },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Spaces { s, range: 4..5 },
+                start: Position { line: 1, column: 5 },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 5..7 },
+                start: Position { line: 1, column: 6 },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Spaces { s, range: 7..8 },
+                start: Position { line: 1, column: 8 },
+            })
+        );
+        // "# trailing note"
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Comment { s, range: 8..23 },
+                start: Position { line: 1, column: 9 },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::Newline,
+                start: Position {
+                    line: 1,
+                    column: 24
+                },
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 24..26 },
+                start: Position { line: 2, column: 1 },
+            })
+        );
+
+        // `#` that doesn't start at a word boundary stays part of the word.
+        let s = "foo#bar";
+        let mut tokenizer = Tokenizer::new(s);
+
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 0..7 },
+                start: Position { line: 1, column: 1 },
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_unquoted() {
+        assert_eq!(decode_unquoted("plain"), MaybeString::Borrowed("plain"));
+        assert_eq!(
+            decode_unquoted(r"foo\ bar"),
+            MaybeString::Owned(String::from("foo bar"))
+        );
+        assert_eq!(
+            decode_unquoted(r"a\#b"),
+            MaybeString::Owned(String::from("a#b"))
+        );
+    }
+
+    #[test]
+    fn test_decode_double_quoted() {
+        assert_eq!(
+            decode_double_quoted("plain"),
+            MaybeString::Borrowed("plain")
+        );
+        assert_eq!(
+            decode_double_quoted(r#"say \"hi\""#),
+            MaybeString::Owned(String::from(r#"say "hi""#))
+        );
+        assert_eq!(
+            decode_double_quoted(r"line1\nline2"),
+            MaybeString::Owned(String::from("line1\nline2"))
+        );
+        // Unrecognized escapes keep the backslash.
+        assert_eq!(
+            decode_double_quoted(r"\q"),
+            MaybeString::Owned(String::from(r"\q"))
+        );
+    }
+
+    #[test]
+    fn test_leading_backslash_stays_with_its_word() {
+        let s = r#"\"quote"#;
+        let mut tokenizer = Tokenizer::new(s);
+
+        assert_eq!(
+            tokenizer.next(),
+            Ok(Spanned {
+                value: Token::String { s, range: 0..7 },
+                start: Position { line: 1, column: 1 },
+            })
+        );
+    }
+
+    #[test]
+    fn test_dangling_backslash_reports_its_own_position() {
+        // A trailing backslash with nothing to escape, at the start of a
+        // word...
+        let s = r"\";
+        let mut tokenizer = Tokenizer::new(s);
+
+        assert_eq!(
+            tokenizer.next(),
+            Err(Error::InvalidEscape {
+                at: Position { line: 1, column: 1 }
+            })
+        );
+
+        // ...and mid-word, both point at the backslash itself.
+        let s = r"a\";
+        let mut tokenizer = Tokenizer::new(s);
+
+        assert_eq!(
+            tokenizer.next(),
+            Err(Error::InvalidEscape {
+                at: Position { line: 1, column: 2 }
+            })
+        );
+    }
+
+    #[test]
+    fn test_peek_token_does_not_advance_position() {
+        let s = "echo world";
+        let mut tokenizer = Tokenizer::new(s);
+
+        assert_eq!(tokenizer.peek_token(), Ok(Token::String { s, range: 0..4 }));
+        assert_eq!(tokenizer.position(), Position { line: 1, column: 1 });
+    }
 }